@@ -1,28 +1,80 @@
 
+use self::lexer::Span;
+
 #[derive(Debug)]
 pub enum QueryError {
 	// Lexing + Parsing
-	LexingError, // FIXME: Lexing currently cannot fail without panic, unused
+	LexingError(String, Span),
 	ParsingError,
 
 	// Execution
-	VariableNotDefined(String),
-	MathError(String),
-	InvalidType(String),
+	VariableNotDefined(String, Option<Span>),
+	MathError(String, Option<Span>),
+	InvalidType(String, Option<Span>),
+}
+
+impl QueryError {
+	// Renders a caret-annotated snippet of `source` underlining the error's span, if any.
+	pub fn render(&self, source: &str) -> String {
+		let (msg, span) = match *self {
+			QueryError::VariableNotDefined(ref m, s) => (format!("variable not defined: {}", m), s),
+			QueryError::MathError(ref m, s) => (m.clone(), s),
+			QueryError::InvalidType(ref m, s) => (m.clone(), s),
+			QueryError::LexingError(ref m, s) => (m.clone(), Some(s)),
+			_ => return format!("{:?}", self),
+		};
+		let span = match span {
+			Some(s) => s,
+			None => return msg,
+		};
+
+		let mut line_start = 0;
+		let mut line_no = 1;
+		for (i, c) in source.char_indices() {
+			if i >= span.lo {
+				break;
+			}
+			if c == '\n' {
+				line_start = i + 1;
+				line_no += 1;
+			}
+		}
+		let line_end = source[line_start..].find('\n').map(|i| line_start + i).unwrap_or(source.len());
+		let line = &source[line_start..line_end];
+		// Counted in chars, not bytes: a multi-byte char earlier on the line
+		// would otherwise throw the caret off.
+		let col = line[..span.lo - line_start].chars().count();
+		let width = source[span.lo..span.hi].chars().count().max(1);
+
+		let prefix = format!("{} | ", line_no);
+		let mut out = format!("{}\n{}{}\n", msg, prefix, line);
+		out.push_str(&" ".repeat(prefix.len() + col));
+		out.push_str(&"^".repeat(width));
+		out
+	}
 }
 
 mod lexer {
     use plex::lexer;
+    use query::QueryError;
 
     #[derive(Debug, Clone)]
     pub enum Token {
         Ident(String),
 
         Return,
+        If,
+        Else,
 
         Number(f64),
         String(String),
         Equals,
+        EqEq,
+        NotEq,
+        Lt,
+        Gt,
+        Le,
+        Ge,
         Plus,
         Minus,
         Star,
@@ -32,11 +84,17 @@ mod lexer {
         RParen,
         LBracket,
         RBracket,
+        LBrace,
+        RBrace,
         Comma,
         Semi,
 
         Whitespace,
         Comment,
+
+        // Not a real token: carries a lexing failure out of `next_token` so
+        // the caller can turn it into a `QueryError::LexingError` with a span.
+        Error(String),
     }
 
     lexer! {
@@ -47,22 +105,32 @@ mod lexer {
         r#"#[^\n]*"# => (Token::Comment, text),
 
         r#"return"# => (Token::Return, text),
-
-		r#"\"[^\"]*\""# => (
-			Token::String(text.to_owned()[1..text.len()-1].to_string()),
-			text
-		),
+        r#"if"# => (Token::If, text),
+        r#"else"# => (Token::Else, text),
+
+		r#""(\\.|[^"\\])*""# => {
+			let inner = &text[1..text.len()-1];
+			(match unescape(inner) {
+				Ok(s) => Token::String(s),
+				Err(msg) => Token::Error(msg),
+			}, text)
+		}
         r#"[0-9]+[\.]?[0-9]*"# => {
             (if let Ok(i) = text.parse() {
                 Token::Number(i)
             } else {
-                // TODO: do not panic, send an error
-                panic!("integer {} is out of range", text)
+                Token::Error(format!("number {} is out of range", text))
             }, text)
         }
 
         r#"[a-zA-Z_][a-zA-Z0-9_]*"# => (Token::Ident(text.to_owned()), text),
 
+        r#"=="# => (Token::EqEq, text),
+        r#"!="# => (Token::NotEq, text),
+        r#"<="# => (Token::Le, text),
+        r#">="# => (Token::Ge, text),
+        r#"<"# => (Token::Lt, text),
+        r#">"# => (Token::Gt, text),
         r#"="# => (Token::Equals, text),
         r#"\+"# => (Token::Plus, text),
         r#"-"# => (Token::Minus, text),
@@ -73,11 +141,59 @@ mod lexer {
         r#"\)"# => (Token::RParen, text),
         r#"\["# => (Token::LBracket, text),
         r#"\]"# => (Token::RBracket, text),
+        r#"\{"# => (Token::LBrace, text),
+        r#"\}"# => (Token::RBrace, text),
         r#","# => (Token::Comma, text),
         r#";"# => (Token::Semi, text),
 
-        // TODO: do not panic, send an error
-        r#"."# => panic!("unexpected character: {}", text),
+        r#"."# => (Token::Error(format!("unexpected character: {}", text)), text),
+    }
+
+    // Unescapes a string literal's body (quotes already stripped).
+    fn unescape(s: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('r') => out.push('\r'),
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('u') => {
+                    let hex = if chars.as_str().starts_with('{') {
+                        chars.next();
+                        let mut hex = String::new();
+                        loop {
+                            match chars.next() {
+                                Some('}') => break,
+                                Some(c) => hex.push(c),
+                                None => return Err(format!("malformed unicode escape: \\u{{{}", hex)),
+                            }
+                        }
+                        hex
+                    } else {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        if hex.chars().count() < 4 {
+                            return Err(format!("malformed unicode escape: \\u{}", hex));
+                        }
+                        hex
+                    };
+                    let code = u32::from_str_radix(&hex, 16)
+                        .map_err(|_| format!("malformed unicode escape: \\u{}", hex))?;
+                    let ch = ::std::char::from_u32(code)
+                        .ok_or_else(|| format!("invalid unicode code point in string: U+{:X}", code))?;
+                    out.push(ch);
+                },
+                Some(other) => return Err(format!("unknown escape sequence: \\{}", other)),
+                None => return Err("string ends with a trailing backslash".to_string()),
+            }
+        }
+        Ok(out)
     }
 
     pub struct Lexer<'a> {
@@ -106,8 +222,8 @@ mod lexer {
     }
 
     impl<'a> Iterator for Lexer<'a> {
-        type Item = (Token, Span);
-        fn next(&mut self) -> Option<(Token, Span)> {
+        type Item = Result<(Token, Span), QueryError>;
+        fn next(&mut self) -> Option<Result<(Token, Span), QueryError>> {
             loop {
                 let tok = if let Some((tok, new_remaining)) = next_token(self.remaining) {
                     self.remaining = new_remaining;
@@ -119,13 +235,58 @@ mod lexer {
                     (Token::Whitespace, _) | (Token::Comment, _) => {
                         continue;
                     }
+                    (Token::Error(msg), span) => {
+                        return Some(Err(QueryError::LexingError(msg, span_in(span, self.original))));
+                    }
                     (tok, span) => {
-                        return Some((tok, span_in(span, self.original)));
+                        return Some(Ok((tok, span_in(span, self.original))));
                     }
                 }
             }
         }
     }
+
+    #[cfg(test)]
+    mod unescape_tests {
+        use super::unescape;
+
+        #[test]
+        fn passes_through_plain_text() {
+            assert_eq!(unescape("hello").unwrap(), "hello");
+        }
+
+        #[test]
+        fn simple_escapes() {
+            assert_eq!(unescape("a\\nb").unwrap(), "a\nb");
+            assert_eq!(unescape("a\\tb").unwrap(), "a\tb");
+            assert_eq!(unescape("a\\rb").unwrap(), "a\rb");
+            assert_eq!(unescape("a\\\"b").unwrap(), "a\"b");
+            assert_eq!(unescape("a\\\\b").unwrap(), "a\\b");
+        }
+
+        #[test]
+        fn unicode_escapes() {
+            assert_eq!(unescape("\\u{41}").unwrap(), "A");
+            assert_eq!(unescape("\\u0041").unwrap(), "A");
+        }
+
+        #[test]
+        fn unknown_escape_errors() {
+            assert!(unescape("a\\qb").is_err());
+        }
+
+        #[test]
+        fn malformed_code_point_errors() {
+            assert!(unescape("\\u{d800}").is_err()); // lone surrogate, not a valid char
+            assert!(unescape("\\u{zzzz}").is_err()); // not hex
+            assert!(unescape("\\u12").is_err()); // too short
+        }
+
+        #[test]
+        fn multibyte_char_in_unbraced_escape_window_errors_without_panicking() {
+            assert!(unescape("\\uaa\u{4e00}bb").is_err());
+        }
+    }
 }
 
 mod ast {
@@ -142,6 +303,16 @@ mod ast {
         pub node: Expr_,
     }
 
+    #[derive(Debug,Clone,Copy,PartialEq)]
+    pub enum CompareOp {
+        Eq,
+        Neq,
+        Lt,
+        Gt,
+        Le,
+        Ge,
+    }
+
     #[derive(Debug,Clone)]
     pub enum Expr_ {
         Add(Box<Expr>, Box<Expr>),
@@ -149,10 +320,11 @@ mod ast {
         Mul(Box<Expr>, Box<Expr>),
         Div(Box<Expr>, Box<Expr>),
         Mod(Box<Expr>, Box<Expr>),
+        Compare(CompareOp, Box<Expr>, Box<Expr>),
+        If(Box<Expr>, Vec<Expr>, Vec<Expr>),
         Var(String),
         Assign(String, Box<Expr>),
-        // TODO: multi-argument functions
-        Function(String, Box<Expr>),
+        Function(String, Vec<Expr>),
         Return(Box<Expr>),
         Number(f64),
         String(String),
@@ -197,9 +369,9 @@ mod parser {
         }
 
         assign: Expr {
-            Ident(fname) LParen assign[a] RParen => Expr {
+            Ident(fname) LParen args[a] RParen => Expr {
                 span: span!(),
-                node: Expr_::Function(fname, Box::new(a)),
+                node: Expr_::Function(fname, a),
             },
             Ident(var) Equals assign[rhs] => Expr {
                 span: span!(),
@@ -208,6 +380,19 @@ mod parser {
             object[o] => o
         }
 
+        args: Vec<Expr> {
+            => vec![],
+            arglist[a] => a,
+        }
+
+        arglist: Vec<Expr> {
+            assign[a] => vec![a],
+            arglist[mut l] Comma assign[a] => {
+                l.push(a);
+                l
+            },
+        }
+
         object: Expr {
             LBracket list[l] RBracket => l,
             LBracket RBracket => Expr {
@@ -216,7 +401,39 @@ mod parser {
                     Expr_::List(Vec::new())
                 }
             },
-            term[o] => o,
+            If assign[cond] LBrace statements[then_] RBrace Else LBrace statements[else_] RBrace => Expr {
+                span: span!(),
+                node: Expr_::If(Box::new(cond), then_, else_),
+            },
+            compare[o] => o,
+        }
+
+        compare: Expr {
+            compare[lhs] EqEq term[rhs] => Expr {
+                span: span!(),
+                node: Expr_::Compare(CompareOp::Eq, Box::new(lhs), Box::new(rhs)),
+            },
+            compare[lhs] NotEq term[rhs] => Expr {
+                span: span!(),
+                node: Expr_::Compare(CompareOp::Neq, Box::new(lhs), Box::new(rhs)),
+            },
+            compare[lhs] Lt term[rhs] => Expr {
+                span: span!(),
+                node: Expr_::Compare(CompareOp::Lt, Box::new(lhs), Box::new(rhs)),
+            },
+            compare[lhs] Gt term[rhs] => Expr {
+                span: span!(),
+                node: Expr_::Compare(CompareOp::Gt, Box::new(lhs), Box::new(rhs)),
+            },
+            compare[lhs] Le term[rhs] => Expr {
+                span: span!(),
+                node: Expr_::Compare(CompareOp::Le, Box::new(lhs), Box::new(rhs)),
+            },
+            compare[lhs] Ge term[rhs] => Expr {
+                span: span!(),
+                node: Expr_::Compare(CompareOp::Ge, Box::new(lhs), Box::new(rhs)),
+            },
+            term[x] => x
         }
 
         list: Expr {
@@ -294,9 +511,184 @@ mod parser {
     }
 }
 
-#[derive(Debug,Clone)]
+// Rewrites a parsed Program before interpretation: folds constant arithmetic
+// and propagates single-assignment literals into later variable references.
+mod optimize {
+    use query::ast::*;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum OptLevel {
+        None,
+        Simple,
+    }
+
+    pub fn optimize(prog: Program, level: OptLevel) -> Program {
+        match level {
+            OptLevel::None => prog,
+            OptLevel::Simple => Program { stmts: optimize_stmts(prog.stmts) },
+        }
+    }
+
+    fn optimize_stmts(stmts: Vec<Expr>) -> Vec<Expr> {
+        let mut assign_counts: HashMap<String, usize> = HashMap::new();
+        for s in &stmts {
+            count_assigns(&s.node, &mut assign_counts);
+        }
+
+        let mut literal_vars: HashMap<String, Expr_> = HashMap::new();
+        let mut out = Vec::with_capacity(stmts.len());
+        for s in stmts {
+            let s = fold_expr(substitute_vars(s, &literal_vars));
+            if let Expr_::Assign(ref var, ref rhs) = s.node {
+                let assigned_once = assign_counts.get(var) == Some(&1);
+                match rhs.node {
+                    Expr_::Number(_) | Expr_::String(_) if assigned_once => {
+                        literal_vars.insert(var.clone(), rhs.node.clone());
+                    },
+                    _ => {
+                        literal_vars.remove(var);
+                    }
+                }
+            }
+            out.push(s);
+        }
+        out
+    }
+
+    // Counts how many times each variable is assigned anywhere in `node`
+    // (including inside `if`/`else` branches), so propagation only fires for
+    // variables that are truly single-assignment in this statement sequence.
+    fn count_assigns(node: &Expr_, counts: &mut HashMap<String, usize>) {
+        match *node {
+            Expr_::Assign(ref var, ref rhs) => {
+                *counts.entry(var.clone()).or_insert(0) += 1;
+                count_assigns(&rhs.node, counts);
+            },
+            Expr_::Add(ref a, ref b) | Expr_::Sub(ref a, ref b) |
+            Expr_::Mul(ref a, ref b) | Expr_::Div(ref a, ref b) |
+            Expr_::Mod(ref a, ref b) => {
+                count_assigns(&a.node, counts);
+                count_assigns(&b.node, counts);
+            },
+            Expr_::Compare(_, ref a, ref b) => {
+                count_assigns(&a.node, counts);
+                count_assigns(&b.node, counts);
+            },
+            Expr_::If(ref cond, ref then_, ref else_) => {
+                count_assigns(&cond.node, counts);
+                for s in then_ {
+                    count_assigns(&s.node, counts);
+                }
+                for s in else_ {
+                    count_assigns(&s.node, counts);
+                }
+            },
+            Expr_::Function(_, ref args) => {
+                for a in args {
+                    count_assigns(&a.node, counts);
+                }
+            },
+            Expr_::Return(ref e) => count_assigns(&e.node, counts),
+            Expr_::List(ref items) => {
+                for i in items {
+                    count_assigns(&i.node, counts);
+                }
+            },
+            Expr_::Var(_) | Expr_::Number(_) | Expr_::String(_) => {}
+        }
+    }
+
+    // Replaces references to single-assignment literal variables with the
+    // literal itself, recursing into `if`/`else` branches with the same map.
+    fn substitute_vars(e: Expr, vars: &HashMap<String, Expr_>) -> Expr {
+        let Expr { span, node } = e;
+        let node = match node {
+            Expr_::Var(name) => match vars.get(&name) {
+                Some(lit) => lit.clone(),
+                None => Expr_::Var(name),
+            },
+            Expr_::Add(a, b) => Expr_::Add(Box::new(substitute_vars(*a, vars)), Box::new(substitute_vars(*b, vars))),
+            Expr_::Sub(a, b) => Expr_::Sub(Box::new(substitute_vars(*a, vars)), Box::new(substitute_vars(*b, vars))),
+            Expr_::Mul(a, b) => Expr_::Mul(Box::new(substitute_vars(*a, vars)), Box::new(substitute_vars(*b, vars))),
+            Expr_::Div(a, b) => Expr_::Div(Box::new(substitute_vars(*a, vars)), Box::new(substitute_vars(*b, vars))),
+            Expr_::Mod(a, b) => Expr_::Mod(Box::new(substitute_vars(*a, vars)), Box::new(substitute_vars(*b, vars))),
+            Expr_::Compare(op, a, b) => Expr_::Compare(op, Box::new(substitute_vars(*a, vars)), Box::new(substitute_vars(*b, vars))),
+            Expr_::If(cond, then_, else_) => Expr_::If(
+                Box::new(substitute_vars(*cond, vars)),
+                then_.into_iter().map(|s| substitute_vars(s, vars)).collect(),
+                else_.into_iter().map(|s| substitute_vars(s, vars)).collect(),
+            ),
+            Expr_::Assign(var, rhs) => Expr_::Assign(var, Box::new(substitute_vars(*rhs, vars))),
+            Expr_::Function(fname, args) => Expr_::Function(fname, args.into_iter().map(|a| substitute_vars(a, vars)).collect()),
+            Expr_::Return(e) => Expr_::Return(Box::new(substitute_vars(*e, vars))),
+            Expr_::List(items) => Expr_::List(items.into_iter().map(|i| substitute_vars(i, vars)).collect()),
+            node @ Expr_::Number(_) | node @ Expr_::String(_) => node,
+        };
+        Expr { span, node }
+    }
+
+    // Folds a single expression bottom-up: arithmetic on two `Number`
+    // literals collapses into one, division only folds when the divisor is
+    // non-zero (so the runtime `MathError` still fires otherwise).
+    fn fold_expr(e: Expr) -> Expr {
+        let Expr { span, node } = e;
+        let node = match node {
+            Expr_::Add(a, b) => {
+                let (a, b) = (fold_expr(*a), fold_expr(*b));
+                match (&a.node, &b.node) {
+                    (&Expr_::Number(x), &Expr_::Number(y)) => Expr_::Number(x + y),
+                    _ => Expr_::Add(Box::new(a), Box::new(b)),
+                }
+            },
+            Expr_::Sub(a, b) => {
+                let (a, b) = (fold_expr(*a), fold_expr(*b));
+                match (&a.node, &b.node) {
+                    (&Expr_::Number(x), &Expr_::Number(y)) => Expr_::Number(x - y),
+                    _ => Expr_::Sub(Box::new(a), Box::new(b)),
+                }
+            },
+            Expr_::Mul(a, b) => {
+                let (a, b) = (fold_expr(*a), fold_expr(*b));
+                match (&a.node, &b.node) {
+                    (&Expr_::Number(x), &Expr_::Number(y)) => Expr_::Number(x * y),
+                    _ => Expr_::Mul(Box::new(a), Box::new(b)),
+                }
+            },
+            Expr_::Div(a, b) => {
+                let (a, b) = (fold_expr(*a), fold_expr(*b));
+                match (&a.node, &b.node) {
+                    (&Expr_::Number(x), &Expr_::Number(y)) if y != 0.0 => Expr_::Number(x / y),
+                    _ => Expr_::Div(Box::new(a), Box::new(b)),
+                }
+            },
+            Expr_::Mod(a, b) => {
+                let (a, b) = (fold_expr(*a), fold_expr(*b));
+                match (&a.node, &b.node) {
+                    (&Expr_::Number(x), &Expr_::Number(y)) => Expr_::Number(x % y),
+                    _ => Expr_::Mod(Box::new(a), Box::new(b)),
+                }
+            },
+            Expr_::Compare(op, a, b) => Expr_::Compare(op, Box::new(fold_expr(*a)), Box::new(fold_expr(*b))),
+            Expr_::If(cond, then_, else_) => Expr_::If(
+                Box::new(fold_expr(*cond)),
+                then_.into_iter().map(fold_expr).collect(),
+                else_.into_iter().map(fold_expr).collect(),
+            ),
+            Expr_::Assign(var, rhs) => Expr_::Assign(var, Box::new(fold_expr(*rhs))),
+            Expr_::Function(fname, args) => Expr_::Function(fname, args.into_iter().map(fold_expr).collect()),
+            Expr_::Return(e) => Expr_::Return(Box::new(fold_expr(*e))),
+            Expr_::List(items) => Expr_::List(items.into_iter().map(fold_expr).collect()),
+            node @ Expr_::Var(_) | node @ Expr_::Number(_) | node @ Expr_::String(_) => node,
+        };
+        Expr { span, node }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
 pub enum DataType {
 	None(),
+	Boolean(bool),
 	Number(f64),
 	String(String),
 	List(Vec<DataType>),
@@ -335,19 +727,26 @@ mod interpret {
 	}
 
     pub fn interpret_prog<'a>(p: &'a Program) -> Result<DataType, QueryError> {
-		let last_i = p.stmts.len()-1;
 		let mut env = get_env();
+		interpret_stmts(&mut env, &p.stmts)
+    }
+
+	fn interpret_stmts<'a>(env: &mut HashMap<&'a str, DataType>, stmts: &'a [Expr]) -> Result<DataType, QueryError> {
+		if stmts.is_empty() {
+			return Ok(DataType::None());
+		}
+		let last_i = stmts.len()-1;
 		let mut i = 0;
-        for expr in &p.stmts {
-            let ret = interpret_expr(&mut env, expr)?;
+		for expr in stmts {
+			let ret = interpret_expr(env, expr)?;
 			// FIXME: This is ugly
 			if i == last_i {
-                return Ok(ret);
-            }
+				return Ok(ret);
+			}
 			i+=1;
-        }
-        panic!("This should be unreachable!");
-    }
+		}
+		panic!("This should be unreachable!");
+	}
 
     fn interpret_expr<'a>(env: &mut HashMap<&'a str, DataType>, expr: &'a Expr) -> Result<DataType, QueryError> {
         use query::ast::Expr_::*;
@@ -357,11 +756,11 @@ mod interpret {
                 let b_res = interpret_expr(env, b)?;
                 let a_num = match a_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot add something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot add something that is not a number!".to_string(), Some(expr.span)))
                 };
                 let b_num = match b_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot add something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot add something that is not a number!".to_string(), Some(expr.span)))
                 };
                 Ok(DataType::Number(a_num+b_num))
             },
@@ -370,11 +769,11 @@ mod interpret {
                 let b_res = interpret_expr(env, b)?;
                 let a_num = match a_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 let b_num = match b_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 Ok(DataType::Number(a_num-b_num))
             },
@@ -383,11 +782,11 @@ mod interpret {
                 let b_res = interpret_expr(env, b)?;
                 let a_num = match a_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 let b_num = match b_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 Ok(DataType::Number(a_num*b_num))
             },
@@ -396,14 +795,14 @@ mod interpret {
                 let b_res = interpret_expr(env, b)?;
                 let a_num = match a_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 let b_num = match b_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 if b_num == 0.0 {
-                    return Err(QueryError::MathError("Tried to divide by zero!".to_string()));
+                    return Err(QueryError::MathError("Tried to divide by zero!".to_string(), Some(expr.span)));
                 }
                 Ok(DataType::Number(a_num/b_num))
             },
@@ -412,14 +811,52 @@ mod interpret {
                 let b_res = interpret_expr(env, b)?;
                 let a_num = match a_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 let b_num = match b_res {
                     DataType::Number(n) => n,
-                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string()))
+                    _ => return Err(QueryError::InvalidType("Cannot sub something that is not a number!".to_string(), Some(expr.span)))
                 };
                 Ok(DataType::Number(a_num%b_num))
             },
+            Compare(op, ref a, ref b) => {
+                let a_res = interpret_expr(env, a)?;
+                let b_res = interpret_expr(env, b)?;
+                let result = match op {
+                    CompareOp::Eq => a_res == b_res,
+                    CompareOp::Neq => a_res != b_res,
+                    CompareOp::Lt | CompareOp::Gt | CompareOp::Le | CompareOp::Ge => {
+                        let a_num = match a_res {
+                            DataType::Number(n) => n,
+                            _ => return Err(QueryError::InvalidType("Cannot compare something that is not a number!".to_string(), Some(expr.span)))
+                        };
+                        let b_num = match b_res {
+                            DataType::Number(n) => n,
+                            _ => return Err(QueryError::InvalidType("Cannot compare something that is not a number!".to_string(), Some(expr.span)))
+                        };
+                        match op {
+                            CompareOp::Lt => a_num < b_num,
+                            CompareOp::Gt => a_num > b_num,
+                            CompareOp::Le => a_num <= b_num,
+                            CompareOp::Ge => a_num >= b_num,
+                            _ => unreachable!()
+                        }
+                    }
+                };
+                Ok(DataType::Boolean(result))
+            },
+            If(ref cond, ref then_stmts, ref else_stmts) => {
+                let cond_res = interpret_expr(env, cond)?;
+                let cond_bool = match cond_res {
+                    DataType::Boolean(b) => b,
+                    _ => return Err(QueryError::InvalidType("if condition must be a boolean".to_string(), Some(expr.span)))
+                };
+                if cond_bool {
+                    interpret_stmts(env, then_stmts)
+                } else {
+                    interpret_stmts(env, else_stmts)
+                }
+            },
             Assign(ref var, ref b) => {
                 let val = interpret_expr(env, b)?;
 				// FIXME: avoid clone, it's slow
@@ -430,7 +867,7 @@ mod interpret {
             Var(ref var) => {
 				match env.get(&var[..]) {
 					Some(v) => Ok(v.clone()),
-					None => Err(QueryError::VariableNotDefined(var.to_string()))
+					None => Err(QueryError::VariableNotDefined(var.to_string(), Some(expr.span)))
 				}
 			},
             Number(lit) => Ok(DataType::Number(lit)),
@@ -440,17 +877,18 @@ mod interpret {
                 println!("{:?}", val);
 				Ok(val)
             },
-            Function(ref fname, ref e) => {
-                let val = interpret_expr(env, e)?;
+            Function(ref fname, ref arg_exprs) => {
 				let mut args = Vec::new();
-				args.push(val);
+				for arg_expr in arg_exprs {
+					args.push(interpret_expr(env, arg_expr)?);
+				}
                 let var = match env.get(&fname[..]) {
                     Some(v) => v,
-                    None => return Err(QueryError::VariableNotDefined(fname.clone()))
+                    None => return Err(QueryError::VariableNotDefined(fname.clone(), Some(expr.span)))
                 };
 				let f = match var {
 					DataType::Function(f) => f,
-					_ => return Err(QueryError::InvalidType(fname.to_string()))
+					_ => return Err(QueryError::InvalidType(fname.to_string(), Some(expr.span)))
 				};
 				f(args)
             },
@@ -466,15 +904,53 @@ mod interpret {
     }
 }
 
+pub use self::optimize::OptLevel;
+
 pub fn query<'a>(code: &str) -> Result<DataType, QueryError> {
-	let lexer = lexer::Lexer::new(code)
-		.inspect(|tok| eprintln!("tok: {:?}", tok));
-	let program = match parser::parse(lexer) {
+	query_with_opt(code, OptLevel::Simple)
+}
+
+pub fn query_with_opt<'a>(code: &str, opt_level: OptLevel) -> Result<DataType, QueryError> {
+	// Lex eagerly so a malformed token short-circuits here instead of
+	// surfacing mid-parse (or, as before, panicking the server thread).
+	let mut tokens = Vec::new();
+	for tok in lexer::Lexer::new(code) {
+		let (tok, span) = tok?;
+		eprintln!("tok: {:?}", tok);
+		tokens.push((tok, span));
+	}
+	let program = match parser::parse(tokens.into_iter()) {
 		Ok(p) => p,
 		Err(e) => {
 			println!("{:?}", e);
 			return Err(QueryError::ParsingError);
 		}
 	};
+	let program = optimize::optimize(program, opt_level);
 	interpret::interpret_prog(&program)
+}
+
+#[cfg(test)]
+mod optimize_tests {
+    use super::*;
+
+    #[test]
+    fn reassigned_var_is_not_propagated() {
+        let result = query("a = 1; b = a; a = 2; c = a; return b + c * 10;").unwrap();
+        assert_eq!(result, DataType::Number(21.0));
+    }
+
+    #[test]
+    fn division_by_folded_zero_still_errors_at_runtime() {
+        match query("return 1 / (2 - 2);") {
+            Err(QueryError::MathError(_, _)) => {},
+            other => panic!("expected MathError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn branch_local_literals_do_not_leak_across_branches() {
+        let result = query("x = 1; if 1 == 2 { x = 99; return x; } else { return x; };").unwrap();
+        assert_eq!(result, DataType::Number(1.0));
+    }
 }
\ No newline at end of file